@@ -0,0 +1,179 @@
+// Copyright (c) 2024 bitfl0wer
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Electricity cost attribution.
+//!
+//! The plugs already report cumulative `energy` (kWh). A [`PriceProvider`]
+//! turns that into money by giving a price per kWh in effect at a given
+//! point in time, either a flat `--price-per-kwh` or live Tibber spot
+//! prices. The HTTP layer then multiplies energy deltas between readings by
+//! the price at the later reading's timestamp.
+
+use std::collections::BTreeMap;
+use std::sync::RwLock;
+
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use chrono::DateTime;
+use serde::Deserialize;
+
+/// Supplies the electricity price (currency units per kWh) in effect at a
+/// given unix timestamp.
+#[async_trait]
+pub trait PriceProvider: Send + Sync {
+    async fn price_at(&self, timestamp: i64) -> Result<f64>;
+}
+
+/// A flat, configured price that never changes.
+pub struct StaticPrice(pub f64);
+
+#[async_trait]
+impl PriceProvider for StaticPrice {
+    async fn price_at(&self, _timestamp: i64) -> Result<f64> {
+        Ok(self.0)
+    }
+}
+
+const TIBBER_API_URL: &str = "https://api.tibber.com/v1-beta/gql";
+
+const PRICE_INFO_QUERY: &str = "{ viewer { homes { currentSubscription { priceInfo { today { total startsAt } tomorrow { total startsAt } } } } } }";
+
+#[derive(Debug, Deserialize)]
+struct TibberResponse {
+    data: TibberData,
+}
+
+#[derive(Debug, Deserialize)]
+struct TibberData {
+    viewer: TibberViewer,
+}
+
+#[derive(Debug, Deserialize)]
+struct TibberViewer {
+    homes: Vec<TibberHome>,
+}
+
+#[derive(Debug, Deserialize)]
+struct TibberHome {
+    #[serde(rename = "currentSubscription")]
+    current_subscription: TibberSubscription,
+}
+
+#[derive(Debug, Deserialize)]
+struct TibberSubscription {
+    #[serde(rename = "priceInfo")]
+    price_info: TibberPriceInfo,
+}
+
+#[derive(Debug, Deserialize)]
+struct TibberPriceInfo {
+    today: Vec<TibberPricePoint>,
+    tomorrow: Vec<TibberPricePoint>,
+}
+
+#[derive(Debug, Deserialize)]
+struct TibberPricePoint {
+    total: f64,
+    #[serde(rename = "startsAt")]
+    starts_at: String,
+}
+
+/// Fetches hourly spot prices from Tibber's GraphQL API and caches them by
+/// the unix timestamp of the start of the hour they apply to.
+pub struct TibberPrice {
+    token: String,
+    client: reqwest::Client,
+    cache: RwLock<BTreeMap<i64, f64>>,
+}
+
+impl TibberPrice {
+    pub fn new(token: String) -> Self {
+        Self {
+            token,
+            client: reqwest::Client::new(),
+            cache: RwLock::new(BTreeMap::new()),
+        }
+    }
+
+    async fn refresh(&self) -> Result<()> {
+        let response: TibberResponse = self
+            .client
+            .post(TIBBER_API_URL)
+            .bearer_auth(&self.token)
+            .json(&serde_json::json!({ "query": PRICE_INFO_QUERY }))
+            .send()
+            .await?
+            .json()
+            .await?;
+
+        let home = response
+            .data
+            .viewer
+            .homes
+            .into_iter()
+            .next()
+            .context("Tibber account has no homes")?;
+        let price_info = home.current_subscription.price_info;
+
+        let mut cache = self.cache.write().unwrap();
+        for point in price_info.today.into_iter().chain(price_info.tomorrow) {
+            let starts_at = DateTime::parse_from_rfc3339(&point.starts_at)?;
+            cache.insert(starts_at.timestamp(), point.total);
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl PriceProvider for TibberPrice {
+    async fn price_at(&self, timestamp: i64) -> Result<f64> {
+        let bucket = (timestamp / 3_600) * 3_600;
+        if let Some(price) = self.cache.read().unwrap().get(&bucket) {
+            return Ok(*price);
+        }
+        self.refresh().await?;
+        self.cache
+            .read()
+            .unwrap()
+            .get(&bucket)
+            .copied()
+            .context("no Tibber price available for the requested timestamp")
+    }
+}
+
+/// Computes, for each reading in `readings` (ascending by timestamp), the
+/// cost incurred since the previous reading: `max(0, energy_delta) *
+/// price_at(timestamp)`. The first reading has no predecessor and costs
+/// nothing. A price lookup failure for one reading (e.g. a Tibber hour with
+/// no published price yet) only costs that one row a `None`; it never fails
+/// the whole series, since missing cost shouldn't mean missing data.
+pub async fn costs_for(
+    provider: &dyn PriceProvider,
+    readings: &[crate::store::Reading],
+) -> Vec<Option<f64>> {
+    let mut costs = Vec::with_capacity(readings.len());
+    let mut previous_energy: Option<f32> = None;
+    for reading in readings {
+        let delta = match previous_energy {
+            Some(prev) => (reading.energy - prev).max(0.0),
+            None => 0.0,
+        };
+        previous_energy = Some(reading.energy);
+        let cost = match provider.price_at(reading.timestamp).await {
+            Ok(price) => Some(delta as f64 * price),
+            Err(e) => {
+                log::warn!(
+                    "Failed to get price for timestamp {}: {:?}",
+                    reading.timestamp,
+                    e
+                );
+                None
+            }
+        };
+        costs.push(cost);
+    }
+    costs
+}
@@ -0,0 +1,133 @@
+// Copyright (c) 2024 bitfl0wer
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Home Assistant MQTT Discovery.
+//!
+//! When `--ha-discovery` is passed, every configured friendly name is
+//! announced to Home Assistant on startup, by publishing one retained config
+//! message per measurement under
+//! `homeassistant/sensor/<node_id>/<object_id>/config`. The startup config is
+//! a stub (no `ieee_addr`/`manufacturer_name`/`model` yet, since those only
+//! arrive with a reading); it is re-published, enriched with the real device
+//! metadata, as soon as the plug's first reading comes in.
+
+use anyhow::Result;
+use log::*;
+use rumqttc::{AsyncClient, QoS};
+use serde::Serialize;
+
+use crate::Device;
+
+#[derive(Debug, Serialize)]
+struct HaDevice {
+    identifiers: Vec<String>,
+    name: String,
+    manufacturer: Option<String>,
+    model: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct DiscoveryConfig {
+    name: String,
+    unique_id: String,
+    state_topic: String,
+    value_template: String,
+    device_class: String,
+    unit_of_measurement: String,
+    state_class: String,
+    device: HaDevice,
+}
+
+struct Measurement {
+    key: &'static str,
+    device_class: &'static str,
+    unit: &'static str,
+    state_class: &'static str,
+}
+
+const MEASUREMENTS: &[Measurement] = &[
+    Measurement {
+        key: "current",
+        device_class: "current",
+        unit: "A",
+        state_class: "measurement",
+    },
+    Measurement {
+        key: "power",
+        device_class: "power",
+        unit: "W",
+        state_class: "measurement",
+    },
+    Measurement {
+        key: "voltage",
+        device_class: "voltage",
+        unit: "V",
+        state_class: "measurement",
+    },
+    Measurement {
+        key: "energy",
+        device_class: "energy",
+        unit: "kWh",
+        state_class: "total_increasing",
+    },
+];
+
+/// Turns a friendly name into something safe to use as an MQTT topic segment
+/// / HA object id (alphanumeric and underscores only).
+fn slug(value: &str) -> String {
+    value
+        .chars()
+        .map(|c| if c.is_alphanumeric() { c } else { '_' })
+        .collect()
+}
+
+/// Publishes the retained HA discovery config messages for `device`, one per
+/// measurement, all grouped under a single HA device block.
+pub async fn publish(client: &AsyncClient, device: &Device, z2m_topic: &str) -> Result<()> {
+    let node_id = slug(&device.friendly_name);
+    let identifier = device
+        .ieee_addr
+        .clone()
+        .unwrap_or_else(|| node_id.clone());
+
+    let ha_device = HaDevice {
+        identifiers: vec![identifier],
+        name: device.friendly_name.clone(),
+        manufacturer: device.manufacturer_name.clone(),
+        model: device.model.clone(),
+    };
+
+    for measurement in MEASUREMENTS {
+        let object_id = format!("{}_{}", node_id, measurement.key);
+        let config = DiscoveryConfig {
+            name: format!("{} {}", device.friendly_name, measurement.key),
+            unique_id: object_id.clone(),
+            state_topic: format!("{}/{}", z2m_topic, device.friendly_name),
+            value_template: format!("{{{{ value_json.{} }}}}", measurement.key),
+            device_class: measurement.device_class.to_string(),
+            unit_of_measurement: measurement.unit.to_string(),
+            state_class: measurement.state_class.to_string(),
+            device: HaDevice {
+                identifiers: ha_device.identifiers.clone(),
+                name: ha_device.name.clone(),
+                manufacturer: ha_device.manufacturer.clone(),
+                model: ha_device.model.clone(),
+            },
+        };
+        let payload = serde_json::to_vec(&config)?;
+        let topic = format!("homeassistant/sensor/{}/{}/config", node_id, object_id);
+        client
+            .publish(&topic, QoS::AtLeastOnce, true, payload)
+            .await?;
+        trace!("Published HA discovery config to {}", topic);
+    }
+
+    info!(
+        "Published Home Assistant discovery for device {}",
+        device.friendly_name
+    );
+    Ok(())
+}
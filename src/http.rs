@@ -0,0 +1,288 @@
+// Copyright (c) 2024 bitfl0wer
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Minimal HTTP server exposing the data accumulated by the
+//! [`crate::store`] as JSON, so it can be pulled into Grafana via the
+//! JSON/Infinity datasource, plus a control endpoint that publishes
+//! Zigbee2MQTT `set` commands to the plugs.
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, RwLock};
+
+use anyhow::Result;
+use axum::extract::{Path, Query, State};
+use axum::http::StatusCode;
+use axum::routing::{get, post};
+use axum::{Json, Router};
+use rumqttc::{AsyncClient, QoS};
+use serde::{Deserialize, Serialize};
+
+use crate::cost::PriceProvider;
+use crate::store::{Reading, Resolution, RetentionConfig, Store};
+
+/// Last known `state`/`child_lock` of a tracked plug, kept in memory and
+/// updated as readings come in, so the dashboard has something to show
+/// immediately after issuing a command via `POST /devices/{name}/set`.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct DeviceState {
+    pub state: Option<String>,
+    pub child_lock: Option<String>,
+    pub updated_at: Option<i64>,
+}
+
+pub type DeviceStates = Arc<RwLock<HashMap<String, DeviceState>>>;
+
+/// Thresholds, in seconds, above which a coarser rollup table is queried
+/// instead of the raw table, so that long-range Grafana dashboards stay
+/// fast. Mirrors the resolutions produced by [`crate::store`].
+const ONE_MINUTE_THRESHOLD: i64 = 86_400; // > 1 day: query device_1m
+const ONE_HOUR_THRESHOLD: i64 = 7 * 86_400; // > 7 days: query device_1h
+const ONE_DAY_THRESHOLD: i64 = 30 * 86_400; // > 30 days: query device_1d
+
+/// Shared state handed to every HTTP handler.
+#[derive(Clone)]
+pub struct AppState {
+    pub store: Arc<dyn Store>,
+    pub friendly_names: Vec<String>,
+    pub price_provider: Option<Arc<dyn PriceProvider>>,
+    pub mqtt_client: AsyncClient,
+    pub z2m_topic: String,
+    pub device_states: DeviceStates,
+    pub retention: RetentionConfig,
+}
+
+/// A reading plus the cost incurred since the previous reading, computed
+/// from the energy delta and the electricity price in effect at the time.
+#[derive(Debug, Serialize)]
+pub struct ReadingWithCost {
+    #[serde(flatten)]
+    pub reading: Reading,
+    pub cost: Option<f64>,
+}
+
+/// Query parameters accepted by `GET /devices/{friendly_name}`.
+#[derive(Debug, Deserialize, Default)]
+pub struct RangeQuery {
+    pub from: Option<i64>,
+    pub to: Option<i64>,
+    pub limit: Option<i64>,
+}
+
+/// Response shape of `GET /data`: every tracked device with its raw series,
+/// oldest first and capped at the default limit of 10,000 readings; newer
+/// readings beyond that cap are not returned.
+#[derive(Debug, Serialize)]
+pub struct DeviceSeries {
+    pub device: String,
+    pub data: Vec<ReadingWithCost>,
+}
+
+async fn list_devices(State(state): State<AppState>) -> Json<Vec<String>> {
+    Json(state.friendly_names)
+}
+
+async fn device_series(
+    Path(friendly_name): Path<String>,
+    Query(range): Query<RangeQuery>,
+    State(state): State<AppState>,
+) -> Json<Vec<ReadingWithCost>> {
+    let readings = query_readings(&state, &friendly_name, &range)
+        .await
+        .unwrap_or_default();
+    Json(readings)
+}
+
+async fn all_devices(State(state): State<AppState>) -> Json<Vec<DeviceSeries>> {
+    let mut out = Vec::with_capacity(state.friendly_names.len());
+    for device in &state.friendly_names {
+        let data = query_readings(&state, device, &RangeQuery::default())
+            .await
+            .unwrap_or_default();
+        out.push(DeviceSeries {
+            device: device.clone(),
+            data,
+        });
+    }
+    Json(out)
+}
+
+/// Body accepted by `POST /devices/{friendly_name}/set`, mirroring the shape
+/// of a Zigbee2MQTT `set` command. At least one of the two fields must be
+/// given; if both are, both are published in the same command.
+#[derive(Debug, Deserialize)]
+pub struct SetCommand {
+    pub state: Option<String>,
+    pub child_lock: Option<String>,
+}
+
+async fn set_device(
+    Path(friendly_name): Path<String>,
+    State(state): State<AppState>,
+    Json(command): Json<SetCommand>,
+) -> Result<Json<serde_json::Value>, (StatusCode, String)> {
+    if !state.friendly_names.contains(&friendly_name) {
+        return Err((
+            StatusCode::NOT_FOUND,
+            format!("unknown device {friendly_name}"),
+        ));
+    }
+
+    let mut payload = serde_json::Map::new();
+    if let Some(value) = &command.state {
+        payload.insert("state".to_string(), serde_json::Value::String(value.clone()));
+    }
+    if let Some(value) = &command.child_lock {
+        payload.insert(
+            "child_lock".to_string(),
+            serde_json::Value::String(value.clone()),
+        );
+    }
+    if payload.is_empty() {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            "expected a \"state\" or \"child_lock\" field".to_string(),
+        ));
+    }
+
+    let topic = format!("{}/{}/set", state.z2m_topic, friendly_name);
+    let body = serde_json::to_vec(&payload)
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    state
+        .mqtt_client
+        .publish(&topic, QoS::AtLeastOnce, false, body)
+        .await
+        .map_err(|e| (StatusCode::BAD_GATEWAY, e.to_string()))?;
+
+    log::info!("Published control command to {}: {:?}", topic, payload);
+
+    {
+        let mut states = state.device_states.write().unwrap();
+        let entry = states.entry(friendly_name).or_default();
+        if command.state.is_some() {
+            entry.state = command.state;
+        }
+        if command.child_lock.is_some() {
+            entry.child_lock = command.child_lock;
+        }
+        entry.updated_at = Some(crate::now_secs().unwrap_or_default());
+    }
+
+    Ok(Json(serde_json::json!({ "published_to": topic })))
+}
+
+async fn device_state(
+    Path(friendly_name): Path<String>,
+    State(state): State<AppState>,
+) -> Json<DeviceState> {
+    let states = state.device_states.read().unwrap();
+    Json(states.get(&friendly_name).cloned().unwrap_or_default())
+}
+
+/// Ranks resolutions from finest to coarsest, so two candidate resolutions
+/// can be compared and the coarser (safer) one picked.
+fn resolution_rank(resolution: Resolution) -> u8 {
+    match resolution {
+        Resolution::Raw => 0,
+        Resolution::OneMinute => 1,
+        Resolution::OneHour => 2,
+        Resolution::OneDay => 3,
+    }
+}
+
+/// Picks the finest resolution whose retention window still reaches back
+/// to `from`. A table that has already pruned rows that old must not be
+/// picked, regardless of the requested span.
+fn resolution_for_age(from: Option<i64>, retention: RetentionConfig) -> Resolution {
+    let Some(from) = from else {
+        return Resolution::Raw;
+    };
+    let age = crate::now_secs().unwrap_or(from) - from;
+    if age > retention.one_hour {
+        Resolution::OneDay
+    } else if age > retention.one_minute {
+        Resolution::OneHour
+    } else if age > retention.raw {
+        Resolution::OneMinute
+    } else {
+        Resolution::Raw
+    }
+}
+
+/// Picks the coarsest resolution that can still satisfy the requested
+/// `from`/`to` range: coarse enough that a wide Grafana window doesn't have
+/// to scan millions of raw rows, and never finer than the finest resolution
+/// whose retention window still covers `from`.
+fn resolution_for_range(range: &RangeQuery, retention: RetentionConfig) -> Resolution {
+    let span = match (range.from, range.to) {
+        (Some(from), Some(to)) => to - from,
+        (Some(from), None) => crate::now_secs().unwrap_or(from) - from,
+        _ => 0,
+    };
+    let span_based = if span > ONE_DAY_THRESHOLD {
+        Resolution::OneDay
+    } else if span > ONE_HOUR_THRESHOLD {
+        Resolution::OneHour
+    } else if span > ONE_MINUTE_THRESHOLD {
+        Resolution::OneMinute
+    } else {
+        Resolution::Raw
+    };
+    let age_based = resolution_for_age(range.from, retention);
+
+    if resolution_rank(age_based) > resolution_rank(span_based) {
+        age_based
+    } else {
+        span_based
+    }
+}
+
+async fn query_readings(
+    state: &AppState,
+    friendly_name: &str,
+    range: &RangeQuery,
+) -> Result<Vec<ReadingWithCost>> {
+    let readings = state
+        .store
+        .query(
+            friendly_name,
+            resolution_for_range(range, state.retention),
+            range.from,
+            range.to,
+            range.limit.unwrap_or(10_000),
+        )
+        .await?;
+
+    let costs = match &state.price_provider {
+        Some(provider) => crate::cost::costs_for(provider.as_ref(), &readings).await,
+        None => Vec::new(),
+    };
+
+    Ok(readings
+        .into_iter()
+        .enumerate()
+        .map(|(i, reading)| ReadingWithCost {
+            cost: costs.get(i).copied().flatten(),
+            reading,
+        })
+        .collect())
+}
+
+/// Builds the router and serves it on `addr` until the process exits.
+pub async fn serve(addr: SocketAddr, state: AppState) -> Result<()> {
+    let app = Router::new()
+        .route("/devices", get(list_devices))
+        .route("/devices/:friendly_name", get(device_series))
+        .route("/devices/:friendly_name/set", post(set_device))
+        .route("/devices/:friendly_name/state", get(device_state))
+        .route("/data", get(all_devices))
+        .with_state(state);
+
+    log::info!("Listening for HTTP requests on {}", addr);
+    let listener = tokio::net::TcpListener::bind(addr).await?;
+    axum::serve(listener, app).await?;
+    Ok(())
+}
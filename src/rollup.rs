@@ -0,0 +1,38 @@
+// Copyright (c) 2024 bitfl0wer
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Periodic driver for the storage layer's rollup and retention logic. The
+//! actual aggregation/pruning SQL lives behind [`crate::store::Store`] so it
+//! works the same regardless of which backend is configured.
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use log::*;
+
+use crate::store::{RetentionConfig, Store};
+
+/// How often the rollup task wakes up to aggregate and prune.
+const ROLLUP_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Runs forever, asking the store to roll up completed buckets and enforce
+/// retention once per [`ROLLUP_INTERVAL`].
+pub async fn run(store: Arc<dyn Store>, retention: RetentionConfig) {
+    let mut interval = tokio::time::interval(ROLLUP_INTERVAL);
+    loop {
+        interval.tick().await;
+        let now = match crate::now_secs() {
+            Ok(now) => now,
+            Err(e) => {
+                error!("Failed to read current time for rollup tick: {:?}", e);
+                continue;
+            }
+        };
+        if let Err(e) = store.rollup_tick(now, retention).await {
+            error!("Rollup tick failed: {:?}", e);
+        }
+    }
+}
@@ -0,0 +1,457 @@
+// Copyright (c) 2024 bitfl0wer
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Storage backend abstraction.
+//!
+//! Everything that used to talk to a hardcoded `SqlitePool` now goes through
+//! the [`Store`] trait, so the collector can be pointed at a local SQLite
+//! file (the default) or at a shared MySQL/Postgres database via `--db-url`.
+
+use anyhow::Result;
+use async_trait::async_trait;
+use log::*;
+use sea_query::{ColumnDef, Expr, Func, Iden, Order, Query, Table};
+use sea_query_binder::SqlxBinder;
+use serde::Serialize;
+
+/// A raw reading as received from a plug, ready to be inserted.
+#[derive(Debug, Clone)]
+pub struct NewReading {
+    pub friendly_name: String,
+    pub timestamp: i64,
+    pub current: f32,
+    pub energy: f32,
+    pub power: i64,
+    pub voltage: i64,
+}
+
+/// A single time series data point, as returned to HTTP clients.
+#[derive(Debug, Serialize, sqlx::FromRow)]
+pub struct Reading {
+    pub timestamp: i64,
+    pub current: f32,
+    pub energy: f32,
+    pub power: i64,
+    pub voltage: i64,
+}
+
+/// Which table a query should be served from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Resolution {
+    Raw,
+    OneMinute,
+    OneHour,
+    OneDay,
+}
+
+/// Retention windows, in seconds, for each resolution below `device_1d`,
+/// which is kept forever.
+#[derive(Debug, Clone, Copy)]
+pub struct RetentionConfig {
+    pub raw: i64,
+    pub one_minute: i64,
+    pub one_hour: i64,
+}
+
+/// Parses retention window specs like `"1d"`, `"7d"`, `"30d"`, `"12h"` into a
+/// number of seconds, for use as a clap value parser.
+pub fn parse_retention(spec: &str) -> Result<i64, String> {
+    let (number, suffix) = spec.split_at(spec.len() - 1);
+    let amount: i64 = number
+        .parse()
+        .map_err(|_| format!("invalid retention window: {spec}"))?;
+    let multiplier = match suffix {
+        "s" => 1,
+        "m" => 60,
+        "h" => 3_600,
+        "d" => 86_400,
+        _ => return Err(format!("unknown retention unit in {spec}, expected s/m/h/d")),
+    };
+    Ok(amount * multiplier)
+}
+
+#[derive(Iden, Clone, Copy)]
+pub enum DeviceTable {
+    Table,
+    Id,
+    FriendlyName,
+    Timestamp,
+    Current,
+    Energy,
+    Power,
+    Voltage,
+}
+
+#[derive(Iden, Clone, Copy)]
+pub enum Device1m {
+    Table,
+    Id,
+    FriendlyName,
+    Timestamp,
+    Current,
+    Energy,
+    Power,
+    Voltage,
+}
+
+#[derive(Iden, Clone, Copy)]
+pub enum Device1h {
+    Table,
+    Id,
+    FriendlyName,
+    Timestamp,
+    Current,
+    Energy,
+    Power,
+    Voltage,
+}
+
+#[derive(Iden, Clone, Copy)]
+pub enum Device1d {
+    Table,
+    Id,
+    FriendlyName,
+    Timestamp,
+    Current,
+    Energy,
+    Power,
+    Voltage,
+}
+
+fn resolution_table(resolution: Resolution) -> Box<dyn Iden> {
+    match resolution {
+        Resolution::Raw => Box::new(DeviceTable::Table),
+        Resolution::OneMinute => Box::new(Device1m::Table),
+        Resolution::OneHour => Box::new(Device1h::Table),
+        Resolution::OneDay => Box::new(Device1d::Table),
+    }
+}
+
+fn bucket_start(timestamp: i64, bucket_secs: i64) -> i64 {
+    (timestamp / bucket_secs) * bucket_secs
+}
+
+/// Database-agnostic storage of readings, with multi-resolution rollups and
+/// retention. Implementations exist for SQLite, MySQL and Postgres; which
+/// one is active is chosen once at startup by [`connect`].
+#[async_trait]
+pub trait Store: Send + Sync {
+    /// Creates the raw table and all rollup tables if they don't exist yet.
+    async fn initialize_schema(&self) -> Result<()>;
+    /// Inserts a single raw reading.
+    async fn insert_raw(&self, reading: &NewReading) -> Result<()>;
+    /// Returns the most recently inserted raw reading for `friendly_name`, if any.
+    async fn last_raw(&self, friendly_name: &str) -> Result<Option<NewReading>>;
+    /// Queries `resolution`'s table for `friendly_name`, optionally bounded by
+    /// `from`/`to` (unix seconds), ordered ascending, capped at `limit` rows.
+    async fn query(
+        &self,
+        friendly_name: &str,
+        resolution: Resolution,
+        from: Option<i64>,
+        to: Option<i64>,
+        limit: i64,
+    ) -> Result<Vec<Reading>>;
+    /// Rolls up the most recently completed buckets into the coarser
+    /// resolutions and prunes rows older than `retention` allows.
+    async fn rollup_tick(&self, now: i64, retention: RetentionConfig) -> Result<()>;
+}
+
+/// Generates a `Store` impl for one sqlx/sea-query backend. The three
+/// backends share identical logic; only the pool type and sea-query
+/// query builder differ.
+macro_rules! impl_store {
+    ($name:ident, $pool:ty, $builder:expr) => {
+        pub struct $name(pub $pool);
+
+        #[async_trait]
+        impl Store for $name {
+            async fn initialize_schema(&self) -> Result<()> {
+                for table in [
+                    resolution_table(Resolution::Raw),
+                    resolution_table(Resolution::OneMinute),
+                    resolution_table(Resolution::OneHour),
+                    resolution_table(Resolution::OneDay),
+                ] {
+                    let sql = Table::create()
+                        .table(table)
+                        .if_not_exists()
+                        .col(
+                            ColumnDef::new(DeviceTable::Id)
+                                .integer()
+                                .auto_increment()
+                                .primary_key(),
+                        )
+                        .col(
+                            ColumnDef::new(DeviceTable::FriendlyName)
+                                .string()
+                                .not_null(),
+                        )
+                        .col(
+                            ColumnDef::new(DeviceTable::Timestamp)
+                                .big_integer()
+                                .not_null(),
+                        )
+                        .col(ColumnDef::new(DeviceTable::Current).float().not_null())
+                        .col(ColumnDef::new(DeviceTable::Energy).float().not_null())
+                        .col(ColumnDef::new(DeviceTable::Power).big_integer().not_null())
+                        .col(ColumnDef::new(DeviceTable::Voltage).big_integer().not_null())
+                        .build($builder);
+                    sqlx::query(&sql).execute(&self.0).await?;
+                }
+                trace!("Initialized schema for {}", stringify!($name));
+                Ok(())
+            }
+
+            async fn insert_raw(&self, reading: &NewReading) -> Result<()> {
+                let (sql, values) = Query::insert()
+                    .into_table(DeviceTable::Table)
+                    .columns([
+                        DeviceTable::FriendlyName,
+                        DeviceTable::Timestamp,
+                        DeviceTable::Current,
+                        DeviceTable::Energy,
+                        DeviceTable::Power,
+                        DeviceTable::Voltage,
+                    ])
+                    .values_panic([
+                        reading.friendly_name.clone().into(),
+                        reading.timestamp.into(),
+                        reading.current.into(),
+                        reading.energy.into(),
+                        reading.power.into(),
+                        reading.voltage.into(),
+                    ])
+                    .build_sqlx($builder);
+                sqlx::query_with(&sql, values).execute(&self.0).await?;
+                Ok(())
+            }
+
+            async fn last_raw(&self, friendly_name: &str) -> Result<Option<NewReading>> {
+                let (sql, values) = Query::select()
+                    .columns([
+                        DeviceTable::FriendlyName,
+                        DeviceTable::Timestamp,
+                        DeviceTable::Current,
+                        DeviceTable::Energy,
+                        DeviceTable::Power,
+                        DeviceTable::Voltage,
+                    ])
+                    .from(DeviceTable::Table)
+                    .and_where(Expr::col(DeviceTable::FriendlyName).eq(friendly_name))
+                    .order_by(DeviceTable::Id, Order::Desc)
+                    .limit(1)
+                    .build_sqlx($builder);
+
+                #[derive(sqlx::FromRow)]
+                struct Row {
+                    friendly_name: String,
+                    timestamp: i64,
+                    current: f32,
+                    energy: f32,
+                    power: i64,
+                    voltage: i64,
+                }
+
+                let row = sqlx::query_as_with::<_, Row, _>(&sql, values)
+                    .fetch_optional(&self.0)
+                    .await?;
+                Ok(row.map(|r| NewReading {
+                    friendly_name: r.friendly_name,
+                    timestamp: r.timestamp,
+                    current: r.current,
+                    energy: r.energy,
+                    power: r.power,
+                    voltage: r.voltage,
+                }))
+            }
+
+            async fn query(
+                &self,
+                friendly_name: &str,
+                resolution: Resolution,
+                from: Option<i64>,
+                to: Option<i64>,
+                limit: i64,
+            ) -> Result<Vec<Reading>> {
+                let mut select = Query::select();
+                select
+                    .columns([
+                        DeviceTable::Timestamp,
+                        DeviceTable::Current,
+                        DeviceTable::Energy,
+                        DeviceTable::Power,
+                        DeviceTable::Voltage,
+                    ])
+                    .from(resolution_table(resolution))
+                    .and_where(Expr::col(DeviceTable::FriendlyName).eq(friendly_name))
+                    .order_by(DeviceTable::Timestamp, Order::Asc)
+                    .limit(limit.max(1) as u64);
+                if let Some(from) = from {
+                    select.and_where(Expr::col(DeviceTable::Timestamp).gte(from));
+                }
+                if let Some(to) = to {
+                    select.and_where(Expr::col(DeviceTable::Timestamp).lte(to));
+                }
+                let (sql, values) = select.build_sqlx($builder);
+                let rows = sqlx::query_as_with::<_, Reading, _>(&sql, values)
+                    .fetch_all(&self.0)
+                    .await?;
+                Ok(rows)
+            }
+
+            async fn rollup_tick(&self, now: i64, retention: RetentionConfig) -> Result<()> {
+                self.aggregate(
+                    DeviceTable::Table,
+                    Device1m::Table,
+                    bucket_start(now, 60) - 60,
+                    60,
+                )
+                .await?;
+                if now % 3_600 < 60 {
+                    self.aggregate(
+                        Device1m::Table,
+                        Device1h::Table,
+                        bucket_start(now, 3_600) - 3_600,
+                        3_600,
+                    )
+                    .await?;
+                }
+                if now % 86_400 < 60 {
+                    self.aggregate(
+                        Device1h::Table,
+                        Device1d::Table,
+                        bucket_start(now, 86_400) - 86_400,
+                        86_400,
+                    )
+                    .await?;
+                }
+
+                self.prune(DeviceTable::Table, now - retention.raw).await?;
+                self.prune(Device1m::Table, now - retention.one_minute)
+                    .await?;
+                self.prune(Device1h::Table, now - retention.one_hour)
+                    .await?;
+                // device_1d is kept forever.
+                Ok(())
+            }
+        }
+
+        impl $name {
+            /// Aggregates every row in `src` whose timestamp falls within
+            /// `[bucket_start, bucket_start + bucket_secs)` into one row per
+            /// friendly name in `dst` (AVG for instantaneous measurements,
+            /// MAX for the monotonic cumulative energy counter).
+            async fn aggregate(
+                &self,
+                src: impl Iden,
+                dst: impl Iden + Copy,
+                bucket_start: i64,
+                bucket_secs: i64,
+            ) -> Result<()> {
+                let (sql, values) = Query::select()
+                    .column(DeviceTable::FriendlyName)
+                    .expr(Func::avg(Expr::col(DeviceTable::Current)))
+                    .expr(Func::max(Expr::col(DeviceTable::Energy)))
+                    .expr(Func::avg(Expr::col(DeviceTable::Power)))
+                    .expr(Func::avg(Expr::col(DeviceTable::Voltage)))
+                    .from(src)
+                    .and_where(Expr::col(DeviceTable::Timestamp).gte(bucket_start))
+                    .and_where(Expr::col(DeviceTable::Timestamp).lt(bucket_start + bucket_secs))
+                    .group_by_col(DeviceTable::FriendlyName)
+                    .build_sqlx($builder);
+
+                // AVG() of a real yields double precision on Postgres, and AVG() of the
+                // integer power/voltage columns yields numeric/DECIMAL on Postgres/MySQL -
+                // decode everything as f64 rather than assuming it round-trips as f32.
+                let rows: Vec<(String, f64, f64, f64, f64)> =
+                    sqlx::query_as_with(&sql, values).fetch_all(&self.0).await?;
+
+                for (friendly_name, current, energy, power, voltage) in rows {
+                    // A missed-tick burst or a restart can re-aggregate a bucket that was
+                    // already written; delete any existing row for this (friendly_name,
+                    // bucket_start) first so re-running a tick replaces it instead of
+                    // duplicating it.
+                    let (sql, values) = Query::delete()
+                        .from_table(dst)
+                        .and_where(Expr::col(DeviceTable::FriendlyName).eq(friendly_name.clone()))
+                        .and_where(Expr::col(DeviceTable::Timestamp).eq(bucket_start))
+                        .build_sqlx($builder);
+                    sqlx::query_with(&sql, values).execute(&self.0).await?;
+
+                    let (sql, values) = Query::insert()
+                        .into_table(dst)
+                        .columns([
+                            DeviceTable::FriendlyName,
+                            DeviceTable::Timestamp,
+                            DeviceTable::Current,
+                            DeviceTable::Energy,
+                            DeviceTable::Power,
+                            DeviceTable::Voltage,
+                        ])
+                        .values_panic([
+                            friendly_name.into(),
+                            bucket_start.into(),
+                            (current as f32).into(),
+                            (energy as f32).into(),
+                            (power.round() as i64).into(),
+                            (voltage.round() as i64).into(),
+                        ])
+                        .build_sqlx($builder);
+                    sqlx::query_with(&sql, values).execute(&self.0).await?;
+                }
+                Ok(())
+            }
+
+            async fn prune(&self, table: impl Iden, older_than: i64) -> Result<()> {
+                let (sql, values) = Query::delete()
+                    .from_table(table)
+                    .and_where(Expr::col(DeviceTable::Timestamp).lt(older_than))
+                    .build_sqlx($builder);
+                sqlx::query_with(&sql, values).execute(&self.0).await?;
+                Ok(())
+            }
+        }
+    };
+}
+
+impl_store!(SqliteStore, sqlx::SqlitePool, sea_query::SqliteQueryBuilder);
+impl_store!(MySqlStore, sqlx::MySqlPool, sea_query::MysqlQueryBuilder);
+impl_store!(
+    PostgresStore,
+    sqlx::PgPool,
+    sea_query::PostgresQueryBuilder
+);
+
+/// Connects to the configured backend. `db_url` takes precedence (scheme
+/// selects `mysql://`/`postgres://`); otherwise falls back to the local
+/// SQLite file named by `sqlite_path` (or `./zpowergraph.db`).
+pub async fn connect(db_url: Option<&str>, sqlite_path: Option<&str>) -> Result<Box<dyn Store>> {
+    let store: Box<dyn Store> = match db_url {
+        Some(url) if url.starts_with("mysql://") => {
+            let pool = sqlx::MySqlPool::connect(url).await?;
+            Box::new(MySqlStore(pool))
+        }
+        Some(url) if url.starts_with("postgres://") || url.starts_with("postgresql://") => {
+            let pool = sqlx::PgPool::connect(url).await?;
+            Box::new(PostgresStore(pool))
+        }
+        Some(url) => {
+            anyhow::bail!("unrecognized --db-url scheme in {url}, expected mysql:// or postgres://")
+        }
+        None => {
+            use sqlx::sqlite::SqliteConnectOptions;
+            let path = sqlite_path.unwrap_or("./zpowergraph.db");
+            let options = SqliteConnectOptions::new()
+                .filename(path)
+                .create_if_missing(true);
+            let pool = sqlx::SqlitePool::connect_with(options).await?;
+            Box::new(SqliteStore(pool))
+        }
+    };
+    store.initialize_schema().await?;
+    Ok(store)
+}